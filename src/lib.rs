@@ -45,12 +45,15 @@
 //! [async_block]: https://github.com/rust-lang/rfcs/blob/master/text/2394-async_await.md#async-blocks-vs-async-closures
 //! [async_yield]: https://github.com/rust-lang/rfcs/blob/master/text/2394-async_await.md#generators-and-streams
 //!
-use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
 use std::pin::Pin;
 use std::sync::Arc;
 
 use futures::task::{Context, Poll};
-use futures::{Future, Stream};
+use futures::{Future, Stream, TryStream};
+use futures::stream::{StreamExt, TryStreamExt};
 
 /// Convenience macro to create an `AsyncStream`.
 ///
@@ -62,6 +65,12 @@ use futures::{Future, Stream};
 /// ```rust ignore
 /// sender.send(item).await
 /// ```
+/// To consume another `Stream` and re-emit transformed items, use the
+/// `for_await!(_pat_ in (_expr_) { .. _body_ ..})` macro, e.g.
+/// `for_await!(x in (upstream) { stream_send!(x * 2); })`. Note the
+/// parentheses around `_expr_`: they are required unless `_expr_` is a
+/// single token, such as a plain variable name. Like `stream_send!`,
+/// `for_await!` is only usable inside the code block, no import needed.
 ///
 /// Adding `move`, as in `async_stream!(_item_, move { .. _code_ ..})`, does
 /// what you expect it to do.
@@ -75,6 +84,18 @@ macro_rules! async_stream {
                             sender.send($item).await
                         };
                     );
+                macro_rules! for_await (
+                        ($pat:pat in $src:tt $body:block) => {{
+                            #[allow(unused_parens)]
+                            let mut __for_await_src = $src;
+                            loop {
+                                match $crate::__for_await_next(&mut __for_await_src).await {
+                                    ::std::option::Option::Some($pat) => $body,
+                                    ::std::option::Option::None => break,
+                                }
+                            }
+                        }};
+                    );
                 $code
             }
         })
@@ -87,6 +108,18 @@ macro_rules! async_stream {
                             sender.send($item).await
                         };
                     );
+                macro_rules! for_await (
+                        ($pat:pat in $src:tt $body:block) => {{
+                            #[allow(unused_parens)]
+                            let mut __for_await_src = $src;
+                            loop {
+                                match $crate::__for_await_next(&mut __for_await_src).await {
+                                    ::std::option::Option::Some($pat) => $body,
+                                    ::std::option::Option::None => break,
+                                }
+                            }
+                        }};
+                    );
                 $code
             }
         })
@@ -106,6 +139,18 @@ macro_rules! async_try_stream {
                             sender.send($item).await
                         };
                     );
+                macro_rules! for_await (
+                        ($pat:pat in $src:tt $body:block) => {{
+                            #[allow(unused_parens)]
+                            let mut __for_await_src = $src;
+                            loop {
+                                match $crate::__for_await_next(&mut __for_await_src).await {
+                                    ::std::option::Option::Some($pat) => $body,
+                                    ::std::option::Option::None => break,
+                                }
+                            }
+                        }};
+                    );
                 $code
             }
         })
@@ -118,12 +163,32 @@ macro_rules! async_try_stream {
                             sender.send($item).await
                         };
                     );
+                macro_rules! for_await (
+                        ($pat:pat in $src:tt $body:block) => {{
+                            #[allow(unused_parens)]
+                            let mut __for_await_src = $src;
+                            loop {
+                                match $crate::__for_await_next(&mut __for_await_src).await {
+                                    ::std::option::Option::Some($pat) => $body,
+                                    ::std::option::Option::None => break,
+                                }
+                            }
+                        }};
+                    );
                 $code
             }
         })
     };
 }
 
+// Hidden helper for the `for_await!` macro, so it doesn't need the
+// caller to import `StreamExt` just to call `.next()`.
+#[doc(hidden)]
+pub async fn __for_await_next<S>(s: &mut S) -> Option<S::Item>
+where S: Stream + Unpin {
+    s.next().await
+}
+
 /// Future returned by the Sender.send() method.
 ///
 /// Completes when the item is sent. _Must_ be `await`ed.
@@ -133,9 +198,11 @@ pub struct SenderFuture {
 }
 
 impl SenderFuture {
-    // constructor. private.
-    fn new() -> SenderFuture {
-        SenderFuture { is_ready: false }
+    // constructor. private. `is_ready` is false if the buffer was
+    // already at capacity when the item was pushed, so the future
+    // must yield once to give the stream a chance to drain it.
+    fn new(is_ready: bool) -> SenderFuture {
+        SenderFuture { is_ready }
     }
 }
 
@@ -154,16 +221,27 @@ impl Future for SenderFuture {
     }
 }
 
+// Shared state between the Sender held by the closure and the one
+// kept by the AsyncStream/AsyncTryStream. Only ever accessed from
+// the task that is polling the stream, so a RefCell is enough.
+struct Inner<I> {
+    queue:    RefCell<VecDeque<I>>,
+    capacity: usize,
+}
+
 // Only internally used by one AsyncStream and never shared
 // in any other way, so we don't have to use Arc<Mutex<..>>.
 /// Type of the sender passed as first argument into the async closure.
-pub struct Sender<I>(Arc<Cell<Option<I>>>);
+pub struct Sender<I>(Arc<Inner<I>>);
 unsafe impl<I> Sync for Sender<I> {}
 unsafe impl<I> Send for Sender<I> {}
 
 impl<I> Sender<I> {
-    fn new() -> Sender<I> {
-        Sender(Arc::new(Cell::new(None)))
+    fn new(capacity: usize) -> Sender<I> {
+        Sender(Arc::new(Inner {
+            queue: RefCell::new(VecDeque::new()),
+            capacity,
+        }))
     }
 
     // note that this is NOT impl Clone for Sender, it's private.
@@ -171,14 +249,57 @@ impl<I> Sender<I> {
         Sender(Arc::clone(&self.0))
     }
 
+    // Pop the oldest buffered item, if any. Used by poll_next.
+    fn pop(&self) -> Option<I> {
+        self.0.queue.borrow_mut().pop_front()
+    }
+
     /// Send one item to the stream.
+    ///
+    /// With the default capacity of `0` this always yields once, just
+    /// like before. With a larger capacity (see `with_capacity`) it
+    /// only yields once the internal buffer is full, so a closure that
+    /// produces several items per tick doesn't pay for a Pending/Ready
+    /// round-trip per item.
     pub fn send<T>(&mut self, item: T) -> SenderFuture
     where T: Into<I> {
-        self.0.set(Some(item.into()));
-        SenderFuture::new()
+        let mut queue = self.0.queue.borrow_mut();
+        let was_full = queue.len() >= self.0.capacity;
+        queue.push_back(item.into());
+        SenderFuture::new(!was_full)
+    }
+
+    /// Send every item of `src` to the stream, in order.
+    ///
+    /// This is the building block for forwarding/transforming an inner
+    /// stream from inside an `async_stream!`/`async_try_stream!` block,
+    /// instead of hand-writing a `while let Some(x) = src.next().await`
+    /// loop around `stream_send!`.
+    pub async fn send_all<S>(&mut self, mut src: S)
+    where S: Stream<Item = I> + Unpin {
+        while let Some(item) = src.next().await {
+            self.send(item).await;
+        }
+    }
+
+    /// Like `send_all`, but for a `TryStream` source: forwards items
+    /// until `src` is exhausted or yields an `Err`, which is returned
+    /// immediately without being sent to the stream.
+    pub async fn try_send_all<S>(&mut self, mut src: S) -> Result<(), S::Error>
+    where S: TryStream<Ok = I> + Unpin {
+        while let Some(item) = src.try_next().await? {
+            self.send(item).await;
+        }
+        Ok(())
     }
 }
 
+// Boxed closure future shared by `AsyncStream` and `AsyncTryStream`. Both
+// store their future as a `Result`-returning one (see the comment on
+// `AsyncStream::fut`) so it can be driven through the same
+// `futures::compat::Compat` machinery under the `compat` feature-flag.
+type BoxTryFuture<Error> = Pin<Box<dyn Future<Output = Result<(), Error>> + 'static + Send>>;
+
 /// Produce items for a stream from an async closure.
 ///
 /// `AsyncStream::new()` takes a [Future][Future03] ([async closure][async_closure], usually),
@@ -187,6 +308,12 @@ impl<I> Sender<I> {
 /// Async closures are not stabilised yet, but you can wrap an async
 /// block in a closure which is very similar, as [documented in the async/await RFC][async_block].
 ///
+/// If the `compat` feature-flag is set, `AsyncStream` will also implement
+/// the [futures 0.1 Stream trait][Stream01], and `AsyncStream::from_01_stream`
+/// lets you build one from a futures 0.1 `Stream`.
+///
+/// [Stream01]: https://docs.rs/futures/0.1.28/futures/stream/trait.Stream.html
+///
 /// Example:
 ///
 /// ```ignore rust
@@ -203,7 +330,18 @@ impl<I> Sender<I> {
 #[must_use]
 pub struct AsyncStream<Item> {
     item: Sender<Item>,
-    fut:  Option<Pin<Box<dyn Future<Output = ()> + 'static + Send>>>,
+    // Stored as a `Result`-returning future (which can never actually be
+    // `Err`) rather than a plain `Future<Output = ()>` so that, with the
+    // `compat` feature-flag, it can be driven through the same
+    // `futures::compat::Compat` wrapper used for `AsyncTryStream` (that
+    // wrapper only implements futures 0.1's `Future` for futures 0.3
+    // `TryFuture`s).
+    fut:  Option<BoxTryFuture<Infallible>>,
+    // Set once the closure's future has resolved. From then on the
+    // future is never polled again; we just keep draining `item`
+    // until it is empty, so nothing sent right before completion
+    // (e.g. the last iteration of a `select!`/`join!`) is lost.
+    done: bool,
 }
 
 impl<Item> AsyncStream<Item> {
@@ -217,10 +355,34 @@ impl<Item> AsyncStream<Item> {
         R: Future<Output = ()> + Send + 'static,
         Item: 'static,
     {
-        let sender = Sender::new();
+        AsyncStream::with_capacity(0, f)
+    }
+
+    /// Create a new Stream from an async closure, with a bounded internal
+    /// buffer sized by `capacity`.
+    ///
+    /// A `capacity` of `0` behaves exactly like `new`: every `send()`
+    /// yields once. A larger capacity lets `capacity` sends complete
+    /// without yielding; the send that finds `capacity` items already
+    /// buffered still queues its item and then yields once, so at most
+    /// `capacity + 1` items are buffered. This amortizes the
+    /// Pending/Ready poll round-trip for producers that emit bursts
+    /// (e.g. from inside a `join!`).
+    pub fn with_capacity<F, R>(capacity: usize, f: F) -> Self
+    where
+        F: FnOnce(Sender<Item>) -> R,
+        R: Future<Output = ()> + Send + 'static,
+        Item: 'static,
+    {
+        let sender = Sender::new(capacity);
+        let fut = f(sender.clone());
         AsyncStream::<Item> {
-            fut:  Some(Box::pin(f(sender.clone()))),
+            fut:  Some(Box::pin(async move {
+                fut.await;
+                Ok(())
+            })),
             item: sender,
+            done: false,
         }
     }
 }
@@ -230,22 +392,40 @@ impl<I> Stream for AsyncStream<I> {
     type Item = I;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<I>> {
+        // Drain the buffer before touching the future again: if it still
+        // has items from a previous poll, hand one back without re-polling.
+        // Once `done` is set, this is the only thing poll_next does: the
+        // future is never polled again.
+        if let Some(item) = self.item.pop() {
+            return Poll::Ready(Some(item));
+        }
+        if self.done {
+            return Poll::Ready(None);
+        }
         let pollres = {
             let fut = self.fut.as_mut().unwrap();
             fut.as_mut().poll(cx)
         };
         match pollres {
-            // If the future returned Poll::Ready, that signals the end of the stream.
-            Poll::Ready(_) => Poll::Ready(None),
+            // The future resolving signals the end of the stream, but it may
+            // have pushed an item right before returning Ready (e.g. as the
+            // last step of a `select!`/`join!`), so drain the buffer before
+            // reporting None.
+            Poll::Ready(_) => {
+                self.done = true;
+                self.fut = None;
+                match self.item.pop() {
+                    Some(item) => Poll::Ready(Some(item)),
+                    None => Poll::Ready(None),
+                }
+            },
             Poll::Pending => {
                 // Pending means that some sub-future returned pending. That sub-future
                 // _might_ have been the SenderFuture returned by Sender.send, so
                 // check if there is an item available in self.item.
-                let mut item = self.item.0.replace(None);
-                if item.is_none() {
-                    Poll::Pending
-                } else {
-                    Poll::Ready(Some(item.take().unwrap()))
+                match self.item.pop() {
+                    Some(item) => Poll::Ready(Some(item)),
+                    None => Poll::Pending,
                 }
             },
         }
@@ -261,16 +441,33 @@ impl<I> Stream for AsyncStream<I> {
 /// This means you can use idiomatic error handling with `?` etcetera.
 ///
 /// If the `compat` feature-flag is set, `AsyncTryStream` will also implement
-/// the [futures 0.1 Stream trait][Stream01].
+/// the [futures 0.1 Stream trait][Stream01], and `AsyncTryStream::from_01_stream`
+/// lets you build one from a futures 0.1 `Stream`.
 ///
 /// [Stream01]: https://docs.rs/futures/0.1.28/futures/stream/trait.Stream.html
 ///
 #[must_use]
 pub struct AsyncTryStream<Item, Error> {
     item: Sender<Item>,
-    fut:  Option<Pin<Box<dyn Future<Output = Result<(), Error>> + 'static + Send>>>,
+    fut:  Option<BoxTryFuture<Error>>,
+    // Set once the closure's future has resolved. From then on the
+    // future is never polled again; we just keep draining `item`
+    // until it is empty, so nothing sent right before completion
+    // (e.g. the last iteration of a `select!`/`join!`) is lost.
+    done:  bool,
+    // An `Err` the closure returned while `item` still had buffered
+    // items; held back until the buffer drains, then emitted.
+    error: Option<Error>,
 }
 
+// The only pinned data is the boxed closure future, which is `Pin<Box<..>>`
+// and therefore already `Unpin` on its own; items sit in the `Sender`'s
+// shared queue and a held-back `Error` in a plain `Option`, never pinned
+// in place. So `AsyncTryStream` can be
+// unconditionally `Unpin`, which lets `poll_next` mutate `done`/`error`
+// through `Pin<&mut Self>` without requiring `Error: Unpin` from callers.
+impl<Item, Error> Unpin for AsyncTryStream<Item, Error> {}
+
 impl<Item, Error: 'static + Send> AsyncTryStream<Item, Error> {
     /// Create a new AsyncTryStream from an async closure.
     ///
@@ -282,10 +479,25 @@ impl<Item, Error: 'static + Send> AsyncTryStream<Item, Error> {
         R: Future<Output = Result<(), Error>> + Send + 'static,
         Item: 'static,
     {
-        let sender = Sender::new();
+        AsyncTryStream::with_capacity(0, f)
+    }
+
+    /// Create a new AsyncTryStream from an async closure, with a bounded
+    /// internal buffer of `capacity` items.
+    ///
+    /// See `AsyncStream::with_capacity` for the semantics of `capacity`.
+    pub fn with_capacity<F, R>(capacity: usize, f: F) -> Self
+    where
+        F: FnOnce(Sender<Item>) -> R,
+        R: Future<Output = Result<(), Error>> + Send + 'static,
+        Item: 'static,
+    {
+        let sender = Sender::new(capacity);
         AsyncTryStream::<Item, Error> {
-            fut:  Some(Box::pin(f(sender.clone()))),
-            item: sender,
+            fut:   Some(Box::pin(f(sender.clone()))),
+            item:  sender,
+            done:  false,
+            error: None,
         }
     }
 }
@@ -295,23 +507,55 @@ impl<I, E> Stream for AsyncTryStream<I, E> {
     type Item = Result<I, E>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<I, E>>> {
+        // Drain the buffer before touching the future again: if it still
+        // has items from a previous poll, hand one back without re-polling.
+        // Once `done` is set, this is the only thing poll_next does (plus
+        // emitting a held-back error once the buffer is empty): the future
+        // is never polled again.
+        if let Some(item) = self.item.pop() {
+            return Poll::Ready(Some(Ok(item)));
+        }
+        if let Some(e) = self.error.take() {
+            return Poll::Ready(Some(Err(e)));
+        }
+        if self.done {
+            return Poll::Ready(None);
+        }
         let pollres = {
             let fut = self.fut.as_mut().unwrap();
             fut.as_mut().poll(cx)
         };
         match pollres {
-            // If the future returned Poll::Ready, that signals the end of the stream.
-            Poll::Ready(Ok(_)) => Poll::Ready(None),
-            Poll::Ready(Err(e)) => Poll::Ready(Some(Err(e))),
+            // The future resolving signals the end of the stream, but it may
+            // have pushed an item right before returning Ready (e.g. as the
+            // last step of a `select!`/`join!`), so drain the buffer before
+            // reporting None. An `Err` is held back the same way.
+            Poll::Ready(Ok(_)) => {
+                self.done = true;
+                self.fut = None;
+                match self.item.pop() {
+                    Some(item) => Poll::Ready(Some(Ok(item))),
+                    None => Poll::Ready(None),
+                }
+            },
+            Poll::Ready(Err(e)) => {
+                self.done = true;
+                self.fut = None;
+                match self.item.pop() {
+                    Some(item) => {
+                        self.error = Some(e);
+                        Poll::Ready(Some(Ok(item)))
+                    },
+                    None => Poll::Ready(Some(Err(e))),
+                }
+            },
             Poll::Pending => {
                 // Pending means that some sub-future returned pending. That sub-future
                 // _might_ have been the SenderFuture returned by Sender.send, so
                 // check if there is an item available in self.item.
-                let mut item = self.item.0.replace(None);
-                if item.is_none() {
-                    Poll::Pending
-                } else {
-                    Poll::Ready(Some(Ok(item.take().unwrap())))
+                match self.item.pop() {
+                    Some(item) => Poll::Ready(Some(Ok(item))),
+                    None => Poll::Pending,
                 }
             },
         }
@@ -320,17 +564,126 @@ impl<I, E> Stream for AsyncTryStream<I, E> {
 
 #[cfg(feature = "compat")]
 mod stream01 {
+    use std::convert::Infallible;
+
     use futures::compat::Compat as Compat03As01;
+    use futures::compat::Compat01As03;
+    use futures::StreamExt;
     use futures01::Async as Async01;
     use futures01::Future as Future01;
     use futures01::Stream as Stream01;
 
+    /// Stream implementation for Futures 0.1.
+    impl<I> Stream01 for crate::AsyncStream<I> {
+        type Item = I;
+        type Error = Infallible;
+
+        fn poll(&mut self) -> Result<Async01<Option<Self::Item>>, Self::Error> {
+            // Drain the buffer before touching the future again: if it
+            // still has items from a previous poll, hand one back without
+            // re-polling. Once `done` is set, this is the only thing poll
+            // does: the future is never polled again.
+            if let Some(item) = self.item.pop() {
+                return Ok(Async01::Ready(Some(item)));
+            }
+            if self.done {
+                return Ok(Async01::Ready(None));
+            }
+            // We use a futures::compat::Compat wrapper to be able to call
+            // the futures 0.3 Future in a futures 0.1 context. Because
+            // the Compat wrapper wants to to take ownership, the future
+            // is stored in an Option which we can temporarily move it out
+            // of, and then move it back in.
+            let mut fut = Compat03As01::new(self.fut.take().unwrap());
+            let pollres = fut.poll();
+            self.fut.replace(fut.into_inner());
+            match pollres {
+                // The future resolving signals the end of the stream, but it
+                // may have pushed an item right before returning Ready, so
+                // drain the buffer before reporting None.
+                Ok(Async01::Ready(_)) => {
+                    self.done = true;
+                    self.fut = None;
+                    match self.item.pop() {
+                        Some(item) => Ok(Async01::Ready(Some(item))),
+                        None => Ok(Async01::Ready(None)),
+                    }
+                },
+                Ok(Async01::NotReady) => match self.item.pop() {
+                    Some(item) => Ok(Async01::Ready(Some(item))),
+                    None => Ok(Async01::NotReady),
+                },
+                Err(never) => match never {},
+            }
+        }
+    }
+
+    impl<Item: 'static + Send> crate::AsyncStream<Item> {
+        /// Create an `AsyncStream` that re-emits the items of a futures
+        /// 0.1 `Stream`.
+        ///
+        /// The 0.1 stream is driven through the 0.1→0.3 `Compat01As03`
+        /// adapter. Since `AsyncStream` has no error channel, an `Err`
+        /// from the source stream simply ends the stream early.
+        pub fn from_01_stream<S>(src: S) -> Self
+        where
+            S: Stream01<Item = Item> + Unpin + Send + 'static,
+            S::Error: Send,
+        {
+            crate::AsyncStream::new(move |mut sender| {
+                async move {
+                    let mut src = Compat01As03::new(src);
+                    while let Some(item) = src.next().await {
+                        match item {
+                            Ok(item) => sender.send(item).await,
+                            Err(_) => break,
+                        }
+                    }
+                }
+            })
+        }
+    }
+
+    impl<Item: 'static + Send, Error: 'static + Send> crate::AsyncTryStream<Item, Error> {
+        /// Create an `AsyncTryStream` that re-emits the items of a futures
+        /// 0.1 `Stream`, stopping and returning its error (if any).
+        ///
+        /// The 0.1 stream is driven through the 0.1→0.3 `Compat01As03`
+        /// adapter.
+        pub fn from_01_stream<S>(src: S) -> Self
+        where S: Stream01<Item = Item, Error = Error> + Unpin + Send + 'static {
+            crate::AsyncTryStream::new(move |mut sender| {
+                async move {
+                    let mut src = Compat01As03::new(src);
+                    while let Some(item) = src.next().await {
+                        sender.send(item?).await;
+                    }
+                    Ok(())
+                }
+            })
+        }
+    }
+
     /// Stream implementation for Futures 0.1.
     impl<I, E> Stream01 for crate::AsyncTryStream<I, E> {
         type Item = I;
         type Error = E;
 
         fn poll(&mut self) -> Result<Async01<Option<Self::Item>>, Self::Error> {
+            // Drain the buffer before touching the future again: if it
+            // still has items from a previous poll, hand one back without
+            // re-polling. Once `done` is set, this is the only thing poll
+            // does (plus emitting a held-back error once the buffer is
+            // empty): the future is never polled again.
+            if let Some(item) = self.item.pop() {
+                return Ok(Async01::Ready(Some(item)));
+            }
+            if let Some(e) = self.error.take() {
+                return Err(e);
+            }
+            if self.done {
+                return Ok(Async01::Ready(None));
+            }
             // We use a futures::compat::Compat wrapper to be able to call
             // the futures 0.3 Future in a futures 0.1 context. Because
             // the Compat wrapper wants to to take ownership, the future
@@ -340,16 +693,32 @@ mod stream01 {
             let pollres = fut.poll();
             self.fut.replace(fut.into_inner());
             match pollres {
-                Ok(Async01::Ready(_)) => Ok(Async01::Ready(None)),
-                Ok(Async01::NotReady) => {
-                    let mut item = self.item.0.replace(None);
-                    if item.is_none() {
-                        Ok(Async01::NotReady)
-                    } else {
-                        Ok(Async01::Ready(item.take()))
+                // The future resolving signals the end of the stream, but it
+                // may have pushed an item right before returning Ready, so
+                // drain the buffer before reporting None.
+                Ok(Async01::Ready(_)) => {
+                    self.done = true;
+                    self.fut = None;
+                    match self.item.pop() {
+                        Some(item) => Ok(Async01::Ready(Some(item))),
+                        None => Ok(Async01::Ready(None)),
+                    }
+                },
+                Ok(Async01::NotReady) => match self.item.pop() {
+                    Some(item) => Ok(Async01::Ready(Some(item))),
+                    None => Ok(Async01::NotReady),
+                },
+                Err(e) => {
+                    self.done = true;
+                    self.fut = None;
+                    match self.item.pop() {
+                        Some(item) => {
+                            self.error = Some(e);
+                            Ok(Async01::Ready(Some(item)))
+                        },
+                        None => Err(e),
                     }
                 },
-                Err(e) => Err(e),
             }
         }
     }